@@ -1,12 +1,7 @@
-use lazy_static::lazy_static;
 use log::trace;
-use std::collections::HashMap;
 use std::env;
 use std::io;
 use std::process;
-use std::sync::atomic::AtomicUsize;
-use std::sync::atomic::Ordering;
-use std::sync::RwLock;
 
 #[derive(Debug)]
 enum PatternKind {
@@ -27,6 +22,7 @@ enum Modifier {
     ZeroOrOne,
     ZeroOrMore,
     OneOrMore,
+    Repeat { min: usize, max: Option<usize> },
 }
 
 #[derive(Debug)]
@@ -35,17 +31,78 @@ struct SubPattern {
     modifier: Option<Modifier>,
 }
 
-lazy_static! {
-    static ref BACKREFS: RwLock<HashMap<usize, String>> = RwLock::new(HashMap::new());
-    static ref NUM_OF_BACKREFS: AtomicUsize = AtomicUsize::new(0);
+/// Byte span of every capture group in a match (index 0 is the whole match, 1..N are `(` groups
+/// in the order they appear), with `None` in a slot for a group that didn't participate.
+type Captures = Vec<Option<(usize, usize)>>;
+
+/// Why [`parse_pattern`] rejected a pattern. [`ParseError`] pairs it with the byte offset into
+/// the original pattern string where the problem was found (nested inside `(...)`/`[...]`,
+/// offsets are relative to the outermost pattern, not the enclosing construct).
+#[derive(Debug)]
+pub enum ParseErrorReason {
+    UnterminatedGroup,
+    UnterminatedClass,
+    UnknownEscape(char),
+    TrailingBackslash,
+    DanglingQuantifier,
+    InvalidRepeatSpec,
+}
+
+#[derive(Debug)]
+pub struct ParseError {
+    pos: usize,
+    reason: ParseErrorReason,
+}
+
+impl ParseError {
+    /// Rebases a nested error's position so it's relative to the start of the outer pattern
+    /// that contained the `(...)`/`[...]` construct it was found inside.
+    fn offset_by(self, base: usize) -> ParseError {
+        ParseError {
+            pos: base + self.pos,
+            reason: self.reason,
+        }
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.reason {
+            ParseErrorReason::UnterminatedGroup => write!(f, "unterminated group"),
+            ParseErrorReason::UnterminatedClass => write!(f, "unterminated character class"),
+            ParseErrorReason::UnknownEscape(c) => write!(f, "unknown escape sequence '\\{c}'"),
+            ParseErrorReason::TrailingBackslash => write!(f, "trailing backslash"),
+            ParseErrorReason::DanglingQuantifier => write!(f, "quantifier with nothing to repeat"),
+            ParseErrorReason::InvalidRepeatSpec => {
+                write!(f, "invalid repeat count, expected {{n}}, {{n,}} or {{n,m}}")
+            }
+        }
+    }
+}
+
+/// Parses `pattern`, assigning each `(...)` group a sequential id starting at 0 as it's
+/// encountered. Groups are numbered per-call rather than through global state, so two calls to
+/// `parse_pattern` (even concurrent ones) never see each other's numbering.
+fn parse_pattern(pattern: &str) -> Result<Vec<SubPattern>, ParseError> {
+    let mut next_group = 0usize;
+    parse_pattern_with_counter(pattern, &mut next_group)
 }
 
-fn parse_pattern(pattern: &str) -> Vec<SubPattern> {
+fn parse_pattern_with_counter(
+    pattern: &str,
+    next_group: &mut usize,
+) -> Result<Vec<SubPattern>, ParseError> {
     let mut subpatterns = vec![];
-    let mut chars = pattern.chars().peekable();
+    let mut chars = pattern.char_indices().peekable();
 
-    while let Some(c) = chars.next() {
+    while let Some((i, c)) = chars.next() {
         let mut sp = match c {
+            '+' | '*' | '?' if subpatterns.is_empty() => {
+                return Err(ParseError {
+                    pos: i,
+                    reason: ParseErrorReason::DanglingQuantifier,
+                });
+            }
             '+' | '*' | '?' => continue, // Handled on the previous iteration. Skip.
             '.' => SubPattern {
                 kind: PatternKind::Any,
@@ -60,60 +117,96 @@ fn parse_pattern(pattern: &str) -> Vec<SubPattern> {
                 modifier: None,
             },
             '(' => {
+                let group_start = i;
                 let mut groups = vec![];
+                let mut seg_start = None;
                 let mut contents = String::new();
 
                 let mut nesting_depth = 0;
-                while let Some(c) = chars.next() {
+                let mut closed = false;
+                while let Some((j, c)) = chars.next() {
                     if c == '(' {
                         nesting_depth += 1;
                     } else if c == ')' {
                         nesting_depth -= 1;
                         if nesting_depth < 0 {
+                            closed = true;
                             break;
                         }
-                    } else if c == '|' {
-                        if nesting_depth == 0 {
-                            groups.push(std::mem::take(&mut contents));
-                            continue;
-                        }
+                    } else if c == '|' && nesting_depth == 0 {
+                        groups.push((seg_start.unwrap_or(j), std::mem::take(&mut contents)));
+                        seg_start = None;
+                        continue;
                     }
+                    seg_start.get_or_insert(j);
                     contents.push(c);
                 }
-                groups.push(contents);
+                if !closed {
+                    return Err(ParseError {
+                        pos: group_start,
+                        reason: ParseErrorReason::UnterminatedGroup,
+                    });
+                }
+                groups.push((seg_start.unwrap_or(group_start + 1), contents));
+
+                let id = *next_group;
+                *next_group += 1;
+
+                let mut parsed_groups = Vec::with_capacity(groups.len());
+                for (seg_start, group) in &groups {
+                    parsed_groups.push(
+                        parse_pattern_with_counter(group, next_group)
+                            .map_err(|e| e.offset_by(*seg_start))?,
+                    );
+                }
 
                 SubPattern {
-                    kind: PatternKind::AlternateGroups(
-                        NUM_OF_BACKREFS.fetch_add(1, Ordering::SeqCst),
-                        groups.iter().map(|group| parse_pattern(group)).collect(),
-                    ),
+                    kind: PatternKind::AlternateGroups(id, parsed_groups),
                     modifier: None,
                 }
             }
             '[' => {
+                let class_start = i;
                 let mut contents = String::new();
-                let mut kind = if let Some(nc) = chars.next() {
-                    if nc == '^' {
-                        PatternKind::NotAlternatives(vec![])
-                    } else {
+                let mut content_start = None;
+                let mut kind = match chars.next() {
+                    Some((_, nc)) if nc == '^' => PatternKind::NotAlternatives(vec![]),
+                    Some((j, nc)) => {
                         contents.push(nc);
+                        content_start = Some(j);
                         PatternKind::Alternatives(vec![])
                     }
-                } else {
-                    unreachable!()
+                    None => {
+                        return Err(ParseError {
+                            pos: class_start,
+                            reason: ParseErrorReason::UnterminatedClass,
+                        });
+                    }
                 };
 
-                while let Some(c) = chars.next() {
+                let mut closed = false;
+                while let Some((j, c)) = chars.next() {
                     if c == ']' {
+                        closed = true;
                         break;
                     }
+                    content_start.get_or_insert(j);
                     contents.push(c);
                 }
+                if !closed {
+                    return Err(ParseError {
+                        pos: class_start,
+                        reason: ParseErrorReason::UnterminatedClass,
+                    });
+                }
 
                 match kind {
                     PatternKind::Alternatives(ref mut v)
                     | PatternKind::NotAlternatives(ref mut v) => {
-                        v.extend(parse_pattern(contents.as_str()).into_iter());
+                        v.extend(
+                            parse_pattern_with_counter(contents.as_str(), next_group)
+                                .map_err(|e| e.offset_by(content_start.unwrap_or(class_start + 1)))?,
+                        );
                     }
                     _ => unreachable!(),
                 }
@@ -124,19 +217,19 @@ fn parse_pattern(pattern: &str) -> Vec<SubPattern> {
                 }
             }
             '\\' => match chars.next() {
-                Some(nc) if nc == '\\' => SubPattern {
+                Some((_, nc)) if nc == '\\' => SubPattern {
                     kind: PatternKind::Literal('\\'),
                     modifier: None,
                 },
-                Some(nc) if nc == 'd' => SubPattern {
+                Some((_, nc)) if nc == 'd' => SubPattern {
                     kind: PatternKind::Digit,
                     modifier: None,
                 },
-                Some(nc) if nc == 'w' => SubPattern {
+                Some((_, nc)) if nc == 'w' => SubPattern {
                     kind: PatternKind::AlphaNumeric,
                     modifier: None,
                 },
-                Some(nc) if nc.is_digit(10) => {
+                Some((_, nc)) if nc.is_digit(10) => {
                     let mut tmp = [0u8; 4];
                     SubPattern {
                         kind: PatternKind::BackRef(
@@ -145,8 +238,18 @@ fn parse_pattern(pattern: &str) -> Vec<SubPattern> {
                         modifier: None,
                     }
                 }
-                Some(_) => todo!(),
-                None => todo!(),
+                Some((_, nc)) => {
+                    return Err(ParseError {
+                        pos: i,
+                        reason: ParseErrorReason::UnknownEscape(nc),
+                    });
+                }
+                None => {
+                    return Err(ParseError {
+                        pos: i,
+                        reason: ParseErrorReason::TrailingBackslash,
+                    });
+                }
             },
             c if c == '\'' => SubPattern {
                 kind: PatternKind::Literal(c),
@@ -158,11 +261,56 @@ fn parse_pattern(pattern: &str) -> Vec<SubPattern> {
             },
         };
 
-        if let Some(nc) = chars.peek() {
+        if let Some(&(brace_pos, nc)) = chars.peek() {
             match nc {
                 '+' => sp.modifier = Some(Modifier::OneOrMore),
                 '*' => sp.modifier = Some(Modifier::ZeroOrMore),
                 '?' => sp.modifier = Some(Modifier::ZeroOrOne),
+                '{' => {
+                    let mut lookahead = chars.clone();
+                    lookahead.next(); // '{'
+                    let mut spec = String::new();
+                    let mut closed = false;
+                    for (_, c) in lookahead.by_ref() {
+                        if c == '}' {
+                            closed = true;
+                            break;
+                        }
+                        spec.push(c);
+                    }
+                    if !closed {
+                        return Err(ParseError {
+                            pos: brace_pos,
+                            reason: ParseErrorReason::InvalidRepeatSpec,
+                        });
+                    }
+
+                    let parse_bound = |s: &str| -> Result<usize, ParseError> {
+                        s.parse::<usize>().map_err(|_| ParseError {
+                            pos: brace_pos,
+                            reason: ParseErrorReason::InvalidRepeatSpec,
+                        })
+                    };
+
+                    let (min, max) = match spec.split_once(',') {
+                        Some((min, "")) => (parse_bound(min)?, None),
+                        Some((min, max)) => (parse_bound(min)?, Some(parse_bound(max)?)),
+                        None => {
+                            let n = parse_bound(&spec)?;
+                            (n, Some(n))
+                        }
+                    };
+                    if max.is_some_and(|max| max < min) {
+                        return Err(ParseError {
+                            pos: brace_pos,
+                            reason: ParseErrorReason::InvalidRepeatSpec,
+                        });
+                    }
+                    sp.modifier = Some(Modifier::Repeat { min, max });
+
+                    // Consume `{spec}` from the real iterator (peek() above didn't advance it).
+                    chars = lookahead;
+                }
                 _ => (),
             }
         };
@@ -170,10 +318,21 @@ fn parse_pattern(pattern: &str) -> Vec<SubPattern> {
         subpatterns.push(sp);
     }
 
-    subpatterns
+    Ok(subpatterns)
+}
+
+/// Byte offset of the subslice `s` within `base`, both of which must come from the same
+/// underlying allocation (every `remaining` slice the matcher works with is `&base[n..]`).
+fn offset_in(base: &str, s: &str) -> usize {
+    s.as_ptr() as usize - base.as_ptr() as usize
 }
 
-fn match_subpattern_kind(remaining: &str, kind: &PatternKind) -> Option<usize> {
+fn match_subpattern_kind(
+    base: &str,
+    remaining: &str,
+    kind: &PatternKind,
+    caps: &mut Captures,
+) -> Option<usize> {
     match kind {
         PatternKind::InputStart => unreachable!(),
         PatternKind::InputEnd => {
@@ -183,16 +342,10 @@ fn match_subpattern_kind(remaining: &str, kind: &PatternKind) -> Option<usize> {
                 None
             }
         }
-        PatternKind::Any => {
-            if !remaining.is_empty() {
-                Some(1)
-            } else {
-                None
-            }
-        }
+        PatternKind::Any => remaining.chars().next().map(|c| c.len_utf8()),
         PatternKind::Literal(l) => {
             if remaining.starts_with(*l) {
-                Some(1)
+                Some(l.len_utf8())
             } else {
                 None
             }
@@ -207,43 +360,47 @@ fn match_subpattern_kind(remaining: &str, kind: &PatternKind) -> Option<usize> {
         },
         PatternKind::Alternatives(v) => {
             for alternative in v {
-                if let Some(offset) = match_subpattern(remaining, alternative) {
+                if let Some(offset) = match_subpattern(base, remaining, alternative, caps) {
                     return Some(offset);
                 }
             }
             None
         }
         PatternKind::NotAlternatives(v) => {
+            let c = remaining.chars().next()?;
             for alternative in v {
-                if let Some(_) = match_subpattern(remaining, alternative) {
+                if let Some(_) = match_subpattern(base, remaining, alternative, caps) {
                     return None;
                 }
             }
 
-            Some(1)
+            Some(c.len_utf8())
         }
-        PatternKind::AlternateGroups(bref, groups) => {
+        PatternKind::AlternateGroups(id, groups) => {
             for g in groups {
-                if let Some(offset) = match_all_subpatterns(remaining, g) {
-                    BACKREFS
-                        .write()
-                        .unwrap()
-                        .entry(*bref)
-                        .or_insert(remaining[..offset].to_string());
+                let saved = caps.clone();
+                if let Some(offset) = match_all_subpatterns(base, remaining, g, caps) {
+                    let start = offset_in(base, remaining);
+                    if *id + 1 >= caps.len() {
+                        caps.resize(*id + 2, None);
+                    }
+                    caps[*id + 1] = Some((start, start + offset));
                     return Some(offset);
                 }
+                // Drop spans recorded by groups nested in a branch that failed part-way.
+                *caps = saved;
             }
 
             None
         }
         PatternKind::BackRef(i) => {
-            if let Some(g) = BACKREFS.read().unwrap().get(&(*i - 1)) {
-                if let Some((start, end)) = match_pattern(remaining, g.as_str(), true) {
-                    if start == 0 {
-                        return Some(end);
-                    }
+            if let Some((start, end)) = caps.get(*i).copied().flatten() {
+                let g = &base[start..end];
+                if remaining.starts_with(g) {
+                    Some(g.len())
+                } else {
+                    None
                 }
-                None
             } else {
                 None
             }
@@ -251,11 +408,16 @@ fn match_subpattern_kind(remaining: &str, kind: &PatternKind) -> Option<usize> {
     }
 }
 
-fn match_subpattern(remaining: &str, sp: &SubPattern) -> Option<usize> {
+fn match_subpattern(
+    base: &str,
+    remaining: &str,
+    sp: &SubPattern,
+    caps: &mut Captures,
+) -> Option<usize> {
     match sp.modifier {
         Some(Modifier::ZeroOrMore) | Some(Modifier::OneOrMore) => {
             let mut still_remaining = remaining;
-            while let Some(offset) = match_subpattern_kind(still_remaining, &sp.kind) {
+            while let Some(offset) = match_subpattern_kind(base, still_remaining, &sp.kind, caps) {
                 still_remaining = &still_remaining[offset..];
                 if still_remaining.is_empty() {
                     break;
@@ -273,20 +435,119 @@ fn match_subpattern(remaining: &str, sp: &SubPattern) -> Option<usize> {
             Some(offset)
         }
         Some(Modifier::ZeroOrOne) => {
-            if let Some(offset) = match_subpattern_kind(remaining, &sp.kind) {
+            if let Some(offset) = match_subpattern_kind(base, remaining, &sp.kind, caps) {
                 Some(offset)
             } else {
                 Some(0)
             }
         }
-        None => match_subpattern_kind(remaining, &sp.kind),
+        Some(Modifier::Repeat { min, max }) => {
+            let mut still_remaining = remaining;
+            let mut count = 0;
+            while max.map(|max| count < max).unwrap_or(true) {
+                let Some(offset) = match_subpattern_kind(base, still_remaining, &sp.kind, caps)
+                else {
+                    break;
+                };
+                still_remaining = &still_remaining[offset..];
+                count += 1;
+                if still_remaining.is_empty() {
+                    break;
+                }
+            }
+
+            // We didn't reach the required minimum.
+            if count < min {
+                return None;
+            }
+
+            Some(remaining.len() - still_remaining.len())
+        }
+        None => match_subpattern_kind(base, remaining, &sp.kind, caps),
     }
 }
 
-fn find_match_start<'a, 'b>(input: &'a str, sp: &'b SubPattern) -> Option<(&'a str, usize)> {
+/// The longest literal run every match of `subpatterns` must begin with, e.g. `error:` in
+/// `error:\d+`. Stops at the first subpattern that isn't an unconditionally-required literal
+/// (anything optional, quantified to a variable count beyond its first instance, or not a plain
+/// `Literal`). Returns `None` when no such prefix exists, so callers fall back to scanning every
+/// offset.
+fn required_literal_prefix(subpatterns: &[SubPattern]) -> Option<String> {
+    let mut prefix = String::new();
+    for sp in subpatterns {
+        match (&sp.kind, &sp.modifier) {
+            (PatternKind::Literal(c), None) => prefix.push(*c),
+            (PatternKind::Literal(c), Some(Modifier::OneOrMore)) => {
+                // At least one instance is guaranteed, but how many more follow is variable, so
+                // the mandatory prefix stops right after this one.
+                prefix.push(*c);
+                break;
+            }
+            _ => break,
+        }
+    }
+    if prefix.is_empty() {
+        None
+    } else {
+        Some(prefix)
+    }
+}
+
+/// Finds the next byte offset in `haystack` at which `prefix` occurs, scanning for `prefix`'s
+/// first byte (memchr-style) and verifying the rest only on a hit, rather than testing the full
+/// matcher at every offset.
+fn find_literal_prefix(haystack: &str, prefix: &str) -> Option<usize> {
+    let needle = prefix.as_bytes();
+    let first = needle[0];
+    let hay = haystack.as_bytes();
+
+    let mut i = 0;
+    while i + needle.len() <= hay.len() {
+        match hay[i..].iter().position(|&b| b == first) {
+            Some(off) => {
+                let candidate = i + off;
+                if candidate + needle.len() > hay.len() {
+                    return None;
+                }
+                if &hay[candidate..candidate + needle.len()] == needle {
+                    return Some(candidate);
+                }
+                i = candidate + 1;
+            }
+            None => return None,
+        }
+    }
+    None
+}
+
+fn find_match_start<'a, 'b>(
+    base: &str,
+    input: &'a str,
+    sp: &'b SubPattern,
+    caps: &mut Captures,
+    literal_prefix: Option<&str>,
+) -> Option<(&'a str, usize)> {
+    if let Some(prefix) = literal_prefix {
+        // `prefix` always starts a candidate on a char boundary (it's a full-needle byte match),
+        // so re-entering by exactly one char's worth of bytes keeps `&input[search_from..]` valid
+        // instead of potentially landing inside a multi-byte character.
+        let first_char_len = prefix.chars().next().unwrap().len_utf8();
+        let mut search_from = 0;
+        while let Some(off) = find_literal_prefix(&input[search_from..], prefix) {
+            let n = search_from + off;
+            trace!("{n} attempt at finding first match (literal prefix hit)...");
+            if let Some(offset) = match_subpattern(base, &input[n..], sp, caps) {
+                trace!("Found first match at {n} offset {offset}");
+                return Some((&input[n + offset..], n));
+            }
+            search_from = n + first_char_len;
+        }
+        return None;
+    }
+
     for n in 0..input.len() {
         trace!("{n} attempt at finding first match...");
-        if let Some(offset) = match_subpattern(&input[n..], sp) {
+        if let Some(offset) = match_subpattern(base, &input[n..], sp, caps) {
             trace!("Found first match at {n} offset {offset}");
             return Some((&input[n + offset..], n));
         }
@@ -294,11 +555,16 @@ fn find_match_start<'a, 'b>(input: &'a str, sp: &'b SubPattern) -> Option<(&'a s
     None
 }
 
-fn match_all_subpatterns(input: &str, subpatterns: &[SubPattern]) -> Option<usize> {
+fn match_all_subpatterns(
+    base: &str,
+    input: &str,
+    subpatterns: &[SubPattern],
+    caps: &mut Captures,
+) -> Option<usize> {
     let mut remaining = input;
     for sp in subpatterns {
         trace!("MATCHING {sp:?} against {remaining}");
-        let Some(offset) = match_subpattern(remaining, sp) else {
+        let Some(offset) = match_subpattern(base, remaining, sp, caps) else {
             return None;
         };
 
@@ -308,38 +574,59 @@ fn match_all_subpatterns(input: &str, subpatterns: &[SubPattern]) -> Option<usiz
     Some(input.len() - remaining.len())
 }
 
-fn match_pattern(
+/// Counts `(...)` groups in `subpatterns`, including nested ones, so callers can size a capture
+/// slot vector up front. Group ids are assigned sequentially starting at 0, so this is just
+/// `max(id) + 1`.
+fn count_groups(subpatterns: &[SubPattern]) -> usize {
+    subpatterns.iter().fold(0, |acc, sp| match &sp.kind {
+        PatternKind::AlternateGroups(id, groups) => {
+            let inner = groups.iter().map(|g| count_groups(g)).max().unwrap_or(0);
+            acc.max(*id + 1).max(inner)
+        }
+        PatternKind::Alternatives(v) | PatternKind::NotAlternatives(v) => acc.max(count_groups(v)),
+        _ => acc,
+    })
+}
+
+/// Backtracking matcher kept around as a fallback for patterns using `BackRef`, which a plain
+/// NFA can't express (a back-reference depends on the text a previous thread actually consumed,
+/// not just which state it's in). Everything else now runs through [`captures_nfa`].
+///
+/// Capture slots are local to this call (index 0 is the whole match, 1..N are groups in the
+/// order they appear), so concurrent or repeated calls never see each other's groups.
+fn captures_backtracking(
     input_line: &str,
-    pattern: &str,
-    force_from_start: bool,
-) -> Option<(usize, usize)> {
-    let mut subpatterns = parse_pattern(pattern);
+    mut subpatterns: Vec<SubPattern>,
+) -> Option<Captures> {
     if subpatterns.is_empty() {
-        return Some((0, 0));
+        return Some(vec![Some((0, 0))]);
     }
 
+    let mut caps = vec![None; 1 + count_groups(&subpatterns)];
+
     // Start by trying to find somewhere in the input where we can start a match.
     // Unless we have a line start pattern ^, in which case we simply drop that pattern and
     // expect matches to start at the beginning of input.
-    let (mut remaining, match_start) = if force_from_start {
+    let (mut remaining, match_start) = if let Some(SubPattern {
+        kind: PatternKind::InputStart,
+        ..
+    }) = subpatterns.first()
+    {
+        subpatterns.remove(0);
         (&input_line[0..], 0)
     } else {
-        if let Some(SubPattern {
-            kind: PatternKind::InputStart,
-            ..
-        }) = subpatterns.first()
-        {
-            subpatterns.remove(0);
-            (&input_line[0..], 0)
-        } else {
-            let Some((remaining, match_start)) =
-                find_match_start(&input_line[0..], subpatterns.first().unwrap())
-            else {
-                return None; // Short-circuit if we couldn't find a match starting point.
-            };
-            subpatterns.remove(0);
-            (remaining, match_start)
-        }
+        let literal_prefix = required_literal_prefix(&subpatterns);
+        let Some((remaining, match_start)) = find_match_start(
+            input_line,
+            &input_line[0..],
+            subpatterns.first().unwrap(),
+            &mut caps,
+            literal_prefix.as_deref(),
+        ) else {
+            return None; // Short-circuit if we couldn't find a match starting point.
+        };
+        subpatterns.remove(0);
+        (remaining, match_start)
     };
 
     // Try to match from there and fail if we cannot at some point.
@@ -347,7 +634,7 @@ fn match_pattern(
     let mut previous_remaining = remaining;
     for sp in &subpatterns {
         trace!("MATCHING {sp:?} against {remaining}");
-        let offset = match match_subpattern(remaining, sp) {
+        let offset = match match_subpattern(input_line, remaining, sp, &mut caps) {
             Some(offset) => offset,
             None => {
                 trace!("Backtracking...");
@@ -370,13 +657,15 @@ fn match_pattern(
                             &previous_remaining[1..]
                         };
 
-                        let Some((_, match_start)) = find_match_start(remaining, sp) else {
+                        let Some((_, match_start)) =
+                            find_match_start(input_line, remaining, sp, &mut caps, None)
+                        else {
                             return None;
                         };
 
                         remaining = &remaining[match_start..];
 
-                        match_subpattern(remaining, sp).unwrap()
+                        match_subpattern(input_line, remaining, sp, &mut caps).unwrap()
                     } else {
                         return None;
                     }
@@ -393,7 +682,455 @@ fn match_pattern(
     }
 
     // We ran out of pattern to match, so we had a match!
-    Some((match_start, input_line.len() - remaining.len()))
+    caps[0] = Some((match_start, input_line.len() - remaining.len()));
+    Some(caps)
+}
+
+/// True if `subpatterns` contains a `BackRef` anywhere, including inside groups and classes.
+/// Patterns like this can't be lowered to a plain NFA, so they're routed to
+/// [`captures_backtracking`] instead.
+fn contains_backref(subpatterns: &[SubPattern]) -> bool {
+    subpatterns.iter().any(|sp| match &sp.kind {
+        PatternKind::BackRef(_) => true,
+        PatternKind::Alternatives(v) | PatternKind::NotAlternatives(v) => contains_backref(v),
+        PatternKind::AlternateGroups(_, groups) => groups.iter().any(|g| contains_backref(g)),
+        _ => false,
+    })
+}
+
+// --- Thompson NFA compiler + Pike VM -------------------------------------------------------
+//
+// Lowers the parsed `Vec<SubPattern>` into a flat instruction list and simulates it
+// breadth-first (Pike's VM, as described for the NFA approach to regex matching), which keeps
+// matching at O(n*m) instead of the backtracking engine's worst-case blowup on patterns like
+// `a*a*` or nested quantifiers.
+
+#[derive(Debug)]
+enum CharClassTest<'a> {
+    Digit,
+    AlphaNumeric,
+    Alternatives(&'a [SubPattern]),
+    NotAlternatives(&'a [SubPattern]),
+}
+
+#[derive(Debug)]
+enum Inst<'a> {
+    Char(char),
+    AnyChar,
+    CharClass(CharClassTest<'a>),
+    AssertEnd,
+    Split(usize, usize),
+    Jump(usize),
+    Save(usize),
+    Match,
+}
+
+struct Program<'a> {
+    insts: Vec<Inst<'a>>,
+    num_slots: usize,
+}
+
+fn compile<'a>(subpatterns: &'a [SubPattern]) -> Program<'a> {
+    let num_slots = 2 + 2 * count_groups(subpatterns);
+    let mut insts = vec![Inst::Save(0)];
+    for sp in subpatterns {
+        compile_subpattern(sp, &mut insts);
+    }
+    insts.push(Inst::Save(1));
+    insts.push(Inst::Match);
+    Program { insts, num_slots }
+}
+
+fn compile_subpattern<'a>(sp: &'a SubPattern, insts: &mut Vec<Inst<'a>>) {
+    match sp.modifier {
+        None => compile_kind(&sp.kind, insts),
+        Some(Modifier::ZeroOrOne) => {
+            // Split L1, L2; L1: <kind>; L2:
+            let split_pc = insts.len();
+            insts.push(Inst::Jump(0)); // placeholder, patched below
+            let l1 = insts.len();
+            compile_kind(&sp.kind, insts);
+            let l2 = insts.len();
+            insts[split_pc] = Inst::Split(l1, l2);
+        }
+        Some(Modifier::ZeroOrMore) => {
+            // L1: Split L2, L3; L2: <kind>; Jump L1; L3:
+            let l1 = insts.len();
+            insts.push(Inst::Jump(0)); // placeholder, patched below
+            let l2 = insts.len();
+            compile_kind(&sp.kind, insts);
+            insts.push(Inst::Jump(l1));
+            let l3 = insts.len();
+            insts[l1] = Inst::Split(l2, l3);
+        }
+        Some(Modifier::OneOrMore) => {
+            // L1: <kind>; Split L1, L2
+            let l1 = insts.len();
+            compile_kind(&sp.kind, insts);
+            let split_pc = insts.len();
+            insts.push(Inst::Jump(0)); // placeholder, patched below
+            let l2 = insts.len();
+            insts[split_pc] = Inst::Split(l1, l2);
+        }
+        Some(Modifier::Repeat { min, max }) => {
+            // `min` mandatory copies, then either `max - min` optional copies (each a `Split`
+            // past the rest, ZeroOrOne-style) or, if unbounded, a trailing `ZeroOrMore` copy.
+            for _ in 0..min {
+                compile_kind(&sp.kind, insts);
+            }
+            match max {
+                Some(max) => {
+                    let mut split_pcs = vec![];
+                    for _ in min..max {
+                        let split_pc = insts.len();
+                        insts.push(Inst::Jump(0)); // placeholder, patched below
+                        compile_kind(&sp.kind, insts);
+                        split_pcs.push(split_pc);
+                    }
+                    let end = insts.len();
+                    for split_pc in split_pcs {
+                        insts[split_pc] = Inst::Split(split_pc + 1, end);
+                    }
+                }
+                None => {
+                    // L1: Split L2, L3; L2: <kind>; Jump L1; L3:
+                    let l1 = insts.len();
+                    insts.push(Inst::Jump(0)); // placeholder, patched below
+                    let l2 = insts.len();
+                    compile_kind(&sp.kind, insts);
+                    insts.push(Inst::Jump(l1));
+                    let l3 = insts.len();
+                    insts[l1] = Inst::Split(l2, l3);
+                }
+            }
+        }
+    }
+}
+
+fn compile_kind<'a>(kind: &'a PatternKind, insts: &mut Vec<Inst<'a>>) {
+    match kind {
+        PatternKind::Literal(c) => insts.push(Inst::Char(*c)),
+        PatternKind::Any => insts.push(Inst::AnyChar),
+        PatternKind::Digit => insts.push(Inst::CharClass(CharClassTest::Digit)),
+        PatternKind::AlphaNumeric => insts.push(Inst::CharClass(CharClassTest::AlphaNumeric)),
+        PatternKind::Alternatives(v) => insts.push(Inst::CharClass(CharClassTest::Alternatives(v))),
+        PatternKind::NotAlternatives(v) => {
+            insts.push(Inst::CharClass(CharClassTest::NotAlternatives(v)))
+        }
+        PatternKind::InputEnd => insts.push(Inst::AssertEnd),
+        PatternKind::InputStart => unreachable!("^ only valid as the first subpattern"),
+        PatternKind::AlternateGroups(id, groups) => {
+            let start_slot = 2 + 2 * id;
+            let end_slot = start_slot + 1;
+            let mut jumps_to_end = vec![];
+            for (idx, group) in groups.iter().enumerate() {
+                if idx + 1 < groups.len() {
+                    let split_pc = insts.len();
+                    insts.push(Inst::Jump(0)); // placeholder, patched below
+                    let branch_pc = insts.len();
+                    insts.push(Inst::Save(start_slot));
+                    for gsp in group {
+                        compile_subpattern(gsp, insts);
+                    }
+                    insts.push(Inst::Save(end_slot));
+                    jumps_to_end.push(insts.len());
+                    insts.push(Inst::Jump(0)); // placeholder, patched below
+                    let next_pc = insts.len();
+                    insts[split_pc] = Inst::Split(branch_pc, next_pc);
+                } else {
+                    insts.push(Inst::Save(start_slot));
+                    for gsp in group {
+                        compile_subpattern(gsp, insts);
+                    }
+                    insts.push(Inst::Save(end_slot));
+                }
+            }
+            let end_pc = insts.len();
+            for fixup in jumps_to_end {
+                insts[fixup] = Inst::Jump(end_pc);
+            }
+        }
+        PatternKind::BackRef(_) => {
+            unreachable!("patterns with backreferences never reach the NFA compiler")
+        }
+    }
+}
+
+fn test_char_class(test: &CharClassTest, c: char) -> bool {
+    match test {
+        CharClassTest::Digit => c.is_ascii_digit(),
+        CharClassTest::AlphaNumeric => c.is_alphanumeric() || c == '_',
+        CharClassTest::Alternatives(v) => {
+            let mut buf = [0u8; 4];
+            let s = c.encode_utf8(&mut buf);
+            v.iter()
+                .any(|alt| match_subpattern(s, s, alt, &mut vec![]).is_some())
+        }
+        CharClassTest::NotAlternatives(v) => {
+            let mut buf = [0u8; 4];
+            let s = c.encode_utf8(&mut buf);
+            !v.iter()
+                .any(|alt| match_subpattern(s, s, alt, &mut vec![]).is_some())
+        }
+    }
+}
+
+struct Thread {
+    pc: usize,
+    slots: Vec<Option<usize>>,
+}
+
+/// Follows `Split`/`Jump`/`Save`/`AssertEnd` without consuming input (the epsilon-closure of
+/// `pc`), appending every character-consuming or `Match` instruction it reaches to `list` in
+/// priority order. `seen` dedupes by pc within the current step so this stays O(program size).
+fn add_thread<'a>(
+    insts: &[Inst<'a>],
+    list: &mut Vec<Thread>,
+    seen: &mut Vec<bool>,
+    pc: usize,
+    mut slots: Vec<Option<usize>>,
+    sp: usize,
+    input_len: usize,
+) {
+    if seen[pc] {
+        return;
+    }
+    seen[pc] = true;
+
+    match &insts[pc] {
+        Inst::Jump(x) => add_thread(insts, list, seen, *x, slots, sp, input_len),
+        Inst::Split(a, b) => {
+            add_thread(insts, list, seen, *a, slots.clone(), sp, input_len);
+            add_thread(insts, list, seen, *b, slots, sp, input_len);
+        }
+        Inst::Save(slot) => {
+            if *slot >= slots.len() {
+                slots.resize(*slot + 1, None);
+            }
+            slots[*slot] = Some(sp);
+            add_thread(insts, list, seen, pc + 1, slots, sp, input_len);
+        }
+        Inst::AssertEnd => {
+            if sp == input_len {
+                add_thread(insts, list, seen, pc + 1, slots, sp, input_len);
+            }
+        }
+        _ => list.push(Thread { pc, slots }),
+    }
+}
+
+/// Runs the Pike VM over `input`, optionally anchoring the search to offset 0. When unanchored
+/// and `literal_prefix` is given, new start threads are only injected at offsets where that
+/// literal occurs, skipping the hopeless positions in between. Returns the slot vector of the
+/// highest-priority thread that reached `Match` (leftmost, then greedy), or `None`.
+fn run_pike_vm(
+    prog: &Program,
+    input: &str,
+    anchored: bool,
+    literal_prefix: Option<&str>,
+) -> Option<Vec<Option<usize>>> {
+    let insts = &prog.insts;
+    let n = insts.len();
+    let indices: Vec<(usize, char)> = input.char_indices().collect();
+    let end = input.len();
+
+    let candidates = if !anchored {
+        literal_prefix.map(|prefix| {
+            // Same char-boundary caveat as `find_match_start`: re-enter by one whole char, not
+            // one byte, since `prefix` may start with a multi-byte character.
+            let first_char_len = prefix.chars().next().unwrap().len_utf8();
+            let mut out = vec![];
+            let mut search_from = 0;
+            while let Some(off) = find_literal_prefix(&input[search_from..], prefix) {
+                let abs = search_from + off;
+                out.push(abs);
+                search_from = abs + first_char_len;
+            }
+            out
+        })
+    } else {
+        None
+    };
+    let mut candidate_idx = 0;
+
+    let mut clist: Vec<Thread> = Vec::new();
+    let mut nlist: Vec<Thread> = Vec::new();
+    let mut seen = vec![false; n];
+    let mut matched: Option<Vec<Option<usize>>> = None;
+
+    let mut step = 0;
+    loop {
+        let sp = if step < indices.len() {
+            indices[step].0
+        } else {
+            end
+        };
+        let c = if step < indices.len() {
+            Some(indices[step].1)
+        } else {
+            None
+        };
+
+        let inject_here = if anchored {
+            step == 0
+        } else {
+            match &candidates {
+                Some(cands) => {
+                    while candidate_idx < cands.len() && cands[candidate_idx] < sp {
+                        candidate_idx += 1;
+                    }
+                    candidate_idx < cands.len() && cands[candidate_idx] == sp
+                }
+                None => true,
+            }
+        };
+
+        if matched.is_none() && inject_here {
+            add_thread(insts, &mut clist, &mut seen, 0, vec![None; prog.num_slots], sp, end);
+        }
+
+        if clist.is_empty() {
+            // A later step might still inject a start thread (a future literal-prefix
+            // candidate), so only give up once that can no longer happen.
+            let can_inject_later = !anchored
+                && match &candidates {
+                    Some(cands) => candidate_idx < cands.len(),
+                    None => true,
+                };
+            if matched.is_some() || !can_inject_later {
+                break;
+            }
+        }
+
+        nlist.clear();
+        let mut seen_n = vec![false; n];
+
+        for t in &clist {
+            match &insts[t.pc] {
+                Inst::Char(lit) => {
+                    if let Some(cc) = c {
+                        if cc == *lit {
+                            add_thread(
+                                insts,
+                                &mut nlist,
+                                &mut seen_n,
+                                t.pc + 1,
+                                t.slots.clone(),
+                                sp + cc.len_utf8(),
+                                end,
+                            );
+                        }
+                    }
+                }
+                Inst::AnyChar => {
+                    if let Some(cc) = c {
+                        add_thread(
+                            insts,
+                            &mut nlist,
+                            &mut seen_n,
+                            t.pc + 1,
+                            t.slots.clone(),
+                            sp + cc.len_utf8(),
+                            end,
+                        );
+                    }
+                }
+                Inst::CharClass(test) => {
+                    if let Some(cc) = c {
+                        if test_char_class(test, cc) {
+                            add_thread(
+                                insts,
+                                &mut nlist,
+                                &mut seen_n,
+                                t.pc + 1,
+                                t.slots.clone(),
+                                sp + cc.len_utf8(),
+                                end,
+                            );
+                        }
+                    }
+                }
+                Inst::Match => {
+                    matched = Some(t.slots.clone());
+                    // Everything after this in `clist` is lower priority than the thread that
+                    // just matched, so it can't produce a better (leftmost/greedier) result.
+                    break;
+                }
+                _ => unreachable!("epsilon instructions are resolved in add_thread"),
+            }
+        }
+
+        std::mem::swap(&mut clist, &mut nlist);
+        seen = seen_n;
+
+        if step == indices.len() {
+            break;
+        }
+        step += 1;
+    }
+
+    matched
+}
+
+/// Runs the NFA engine and returns the span of every capture group (index 0 is the whole match,
+/// 1..N are `(` groups in the order they appear), mirroring [`captures_backtracking`].
+fn captures_nfa(
+    input_line: &str,
+    subpatterns: &[SubPattern],
+) -> Option<Captures> {
+    let starts_anchored = matches!(
+        subpatterns.first(),
+        Some(SubPattern {
+            kind: PatternKind::InputStart,
+            ..
+        })
+    );
+    let rest = if starts_anchored {
+        &subpatterns[1..]
+    } else {
+        subpatterns
+    };
+
+    let program = compile(rest);
+    let literal_prefix = required_literal_prefix(rest);
+    let slots = run_pike_vm(&program, input_line, starts_anchored, literal_prefix.as_deref())?;
+    Some(
+        slots
+            .chunks(2)
+            .map(|pair| match (pair[0], pair[1]) {
+                (Some(start), Some(end)) => Some((start, end)),
+                _ => None,
+            })
+            .collect(),
+    )
+}
+
+/// Returns the byte span of the whole match (index 0) and every `(` capture group (1..N, in the
+/// order they appear in `pattern`), `Ok(None)` if `pattern` doesn't match anywhere in `input`, or
+/// `Err` if `pattern` itself is malformed.
+pub fn captures(
+    input: &str,
+    pattern: &str,
+) -> Result<Option<Captures>, ParseError> {
+    let subpatterns = parse_pattern(pattern)?;
+    if subpatterns.is_empty() {
+        return Ok(Some(vec![Some((0, 0))]));
+    }
+
+    Ok(if contains_backref(&subpatterns) {
+        captures_backtracking(input, subpatterns)
+    } else {
+        captures_nfa(input, &subpatterns)
+    })
+}
+
+/// Returns just the whole-match span (index 0 of [`captures`]), for callers that don't need
+/// group spans.
+fn match_pattern(
+    input_line: &str,
+    pattern: &str,
+) -> Result<Option<(usize, usize)>, ParseError> {
+    Ok(captures(input_line, pattern)?.and_then(|caps| caps[0]))
 }
 
 // Usage: echo <input_text> | your_program.sh -E <pattern>
@@ -403,29 +1140,46 @@ fn main() {
     // You can use print statements as follows for debugging, they'll be visible when running tests.
     println!("Logs from your program will appear here!");
 
-    if env::args().nth(1).unwrap() != "-E" {
+    let Some(flag) = env::args().nth(1) else {
+        println!("Usage: your_program.sh -E <pattern>");
+        process::exit(1);
+    };
+    if flag != "-E" {
         println!("Expected first argument to be '-E'");
         process::exit(1);
     }
 
-    let pattern = env::args().nth(2).unwrap();
+    let Some(pattern) = env::args().nth(2) else {
+        println!("Usage: your_program.sh -E <pattern>");
+        process::exit(1);
+    };
     let mut input_line = String::new();
 
-    io::stdin().read_line(&mut input_line).unwrap();
-
-    if let Some((start, end)) = match_pattern(&input_line, &pattern, false) {
-        let bold = "\x1b[1m";
-        let regular = "\x1b[22m";
-        println!(
-            "{}{}{}{}{}",
-            &input_line[..start],
-            bold,
-            &input_line[start..end],
-            regular,
-            &input_line[end..]
-        );
-        process::exit(0)
-    } else {
-        process::exit(1)
+    if io::stdin().read_line(&mut input_line).is_err() {
+        println!("Failed to read input from stdin");
+        process::exit(1);
+    }
+
+    match match_pattern(&input_line, &pattern) {
+        Ok(Some((start, end))) => {
+            let bold = "\x1b[1m";
+            let regular = "\x1b[22m";
+            println!(
+                "{}{}{}{}{}",
+                &input_line[..start],
+                bold,
+                &input_line[start..end],
+                regular,
+                &input_line[end..]
+            );
+            process::exit(0)
+        }
+        Ok(None) => process::exit(1),
+        Err(err) => {
+            println!("error: {err} in pattern");
+            println!("{pattern}");
+            println!("{}^", " ".repeat(err.pos));
+            process::exit(2)
+        }
     }
 }